@@ -1,6 +1,17 @@
+use std::convert::TryInto;
+
 /// A trait for a type that can
 /// work as a bitfield.
-pub trait BitField {
+///
+/// `Copy` plus the bitwise-op supertraits are required because `Query`
+/// evaluates its command stack by combining words with `&`, `|` and
+/// `!` and by reading words out of storage by value; every integer
+/// impl below gets all four for free.
+pub trait BitField:
+		Copy
+		+ std::ops::BitAnd<Output = Self>
+		+ std::ops::BitOr<Output = Self>
+		+ std::ops::Not<Output = Self> {
 	/// Creates a bitfield with no bits
 	/// set
 	fn empty() -> Self;
@@ -15,6 +26,28 @@ pub trait BitField {
 
 	/// Returns the number of bits.
 	fn n_bits() -> usize;
+
+	/// Returns the number of trailing zero bits, i.e. the index
+	/// of the lowest set bit. If no bits are set, this is the full
+	/// width of the underlying type, `byte_width() * 8` (32 for `u32`).
+	fn trailing_zeros(&self) -> u32;
+
+	/// Returns true if no bits are set.
+	fn is_zero(&self) -> bool;
+
+	/// Returns the number of set bits.
+	fn count_ones(&self) -> u32;
+
+	/// The number of bytes `write_le_bytes` writes and `read_le_bytes`
+	/// expects to read.
+	fn byte_width() -> usize;
+
+	/// Appends this field's raw bits, little-endian, to `out`.
+	fn write_le_bytes(&self, out: &mut Vec<u8>);
+
+	/// Reads a field back from exactly `byte_width()` little-endian
+	/// bytes, as written by `write_le_bytes`.
+	fn read_le_bytes(bytes: &[u8]) -> Self;
 }
 
 /// Implements the BitField trait for a numeric type
@@ -28,7 +61,7 @@ macro_rules! impl_bitfield {
 					*self |= (1 << n);
 				}else{
 					*self &= !(1 << n);
-				}	
+				}
 			}
 
 			fn get_bit(&self, n: usize) -> bool {
@@ -36,6 +69,30 @@ macro_rules! impl_bitfield {
 			}
 
 			fn n_bits() -> usize { 8 }
+
+			fn trailing_zeros(&self) -> u32 {
+				<$t>::trailing_zeros(*self)
+			}
+
+			fn is_zero(&self) -> bool {
+				*self == 0
+			}
+
+			fn count_ones(&self) -> u32 {
+				<$t>::count_ones(*self)
+			}
+
+			fn byte_width() -> usize {
+				std::mem::size_of::<$t>()
+			}
+
+			fn write_le_bytes(&self, out: &mut Vec<u8>) {
+				out.extend_from_slice(&self.to_le_bytes());
+			}
+
+			fn read_le_bytes(bytes: &[u8]) -> Self {
+				<$t>::from_le_bytes(bytes.try_into().unwrap())
+			}
 		}
 	}
 }
@@ -66,4 +123,29 @@ mod test {
 		bits.set_bit(5, false);
 		assert_eq!(bits.get_bit(5), false);
 	}
+
+	#[test]
+	fn trailing_zeros_and_is_zero() {
+		assert_eq!(0u32.is_zero(), true);
+		assert_eq!(0u32.trailing_zeros(), 32);
+
+		let bits = 0b00101000u32;
+		assert_eq!(bits.is_zero(), false);
+		assert_eq!(bits.trailing_zeros(), 3);
+	}
+
+	#[test]
+	fn count_ones() {
+		assert_eq!(0u32.count_ones(), 0);
+		assert_eq!(0b00101000u32.count_ones(), 2);
+		assert_eq!(0xFFFFFFFFu32.count_ones(), 32);
+	}
+
+	#[test]
+	fn le_byte_round_trip() {
+		let mut bytes = Vec::new();
+		0xDEADBEEFu32.write_le_bytes(&mut bytes);
+		assert_eq!(bytes.len(), u32::byte_width());
+		assert_eq!(u32::read_le_bytes(&bytes), 0xDEADBEEFu32);
+	}
 }
@@ -18,8 +18,12 @@ impl<T: BitField> DynamicBitField<T> {
 	// 	}
 	// }
 
-	pub(crate) fn data<'a>(&'a self) -> &'a [T] {
-		&self.data[..]
+	/// Reconstructs a DynamicBitField from raw words and a bit length,
+	/// as previously returned by `data()`/`len()`. The words are taken
+	/// as-is, so the caller must uphold the same invariant `push`
+	/// upholds: bits at or past `len` are zero.
+	pub(crate) fn from_raw(data: Vec<T>, len: usize) -> DynamicBitField<T> {
+		DynamicBitField { data, len }
 	}
 
 	/// Creates a DynamicBitField with n_bits bits set to false
@@ -56,17 +60,32 @@ impl<T: BitField> DynamicBitField<T> {
 		self.data[data_index].set_bit(bit_index, value);
 	}
 
-	// pub(crate) fn set_unchecked(&mut self, index: usize, value: bool) {
-	// 	let (data_index, bit_index) = get_indices::<T>(index);
-	// 	self.data[data_index].set_bit(bit_index, value);
-	// }
+	/// Sets the bit at `index` to `value`, overwriting whatever was
+	/// there before.
+	/// Panics if the index is out of bounds
+	pub(crate) fn set_unchecked(&mut self, index: usize, value: bool) {
+		let (data_index, bit_index) = get_indices::<T>(index);
+		self.data[data_index].set_bit(bit_index, value);
+	}
 
 	/// Returns a value at the index.
-	/// Panics if the index is out of bounds 
+	/// Panics if the index is out of bounds
 	pub(crate) fn get_unchecked(&self, index: usize) -> bool {
 		let (data_index, bit_index) = get_indices::<T>(index);
 		self.data[data_index].get_bit(bit_index)
 	}
+
+	/// Returns the `data_index`'th word, or an empty word if there is
+	/// no such word. This exists so `Query` can fetch a word the same
+	/// way regardless of whether a tag is backed by a
+	/// `DynamicBitField` or a `SparseBitField`.
+	pub(crate) fn word_at(&self, data_index: usize) -> T {
+		if data_index < self.data.len() {
+			self.data[data_index]
+		} else {
+			T::empty()
+		}
+	}
 }
 
 /// Returns the (data index, local bit index) pair for
@@ -98,4 +117,31 @@ mod test {
 
 		assert_eq!(field.get_unchecked(100), true);
 	}
+
+	#[test]
+	fn set_unchecked_overwrites_a_bit() {
+		let mut field = DynamicBitField::<u32>::with_false(0);
+		field.push(true);
+		field.push(true);
+
+		field.set_unchecked(0, false);
+		assert_eq!(field.get_unchecked(0), false);
+		assert_eq!(field.get_unchecked(1), true);
+
+		field.set_unchecked(1, false);
+		assert_eq!(field.get_unchecked(1), false);
+	}
+
+	#[test]
+	fn word_at_reads_every_word_by_value() {
+		let mut field = DynamicBitField::<u8>::with_false(0);
+		for _ in 0..8 {
+			field.push(true);
+		}
+		field.push(true);
+
+		assert_eq!(field.word_at(0), 0xFF);
+		assert_eq!(field.word_at(1), 0b1);
+		assert_eq!(field.word_at(2), 0);
+	}
 }
@@ -1,8 +1,12 @@
 use std::collections::HashMap;
+use std::convert::TryInto;
 use std::hash::Hash;
 
 mod dyn_bit_field;
 use dyn_bit_field::DynamicBitField;
+mod sparse_bit_field;
+mod tag_storage;
+use tag_storage::TagStorage;
 mod bit_field;
 mod query;
 
@@ -29,7 +33,11 @@ pub use query::expressions;
 /// a thing with this datatype too much.
 pub struct TagVec<T, F = u32>
 		where T: Eq + Hash + Clone, F: BitField {
-	tag_fields: HashMap<T, DynamicBitField<F>>,
+	tag_fields: HashMap<T, TagStorage<F>>,
+	/// Tracks which element indices have been `remove`d, so `Query`
+	/// can skip them without every tag field having to physically
+	/// shift its bits. See `remove` and `compact`.
+	deleted: DynamicBitField<F>,
 	len: usize,
 }
 
@@ -39,11 +47,14 @@ impl<T: Eq + Hash + Clone, F: BitField> TagVec<T, F> {
 	pub fn new() -> TagVec<T, F> {
 		TagVec {
 			tag_fields: HashMap::new(),
+			deleted: DynamicBitField::with_false(0),
 			len: 0,
 		}
 	}
 
-	/// The number of elements in the TagVec
+	/// The number of element slots in the TagVec, including any
+	/// tombstoned by `remove` - it only shrinks once `compact` reclaims
+	/// them. For how many elements actually match a query, use `count`.
 	pub fn len(&self) -> usize { self.len }
 
 	/// Pushes a new element onto the bitvec,
@@ -79,14 +90,87 @@ impl<T: Eq + Hash + Clone, F: BitField> TagVec<T, F> {
 		// Create new tag fields for tags that appeared just now
 		// This shouldn't run too often since there are fewer tags than values hopefully
 		for skipped_tag in skipped_tags {
-			let mut new_field = DynamicBitField::with_false(self.len());
+			let mut new_field = TagStorage::with_false(self.len());
 			new_field.push(true); // This is the first element to have the tag
 			self.tag_fields.insert(skipped_tag, new_field);
 		}
 
+		self.deleted.push(false); // The new element isn't deleted
 		self.len += 1;
 	}
 
+	/// Removes the element at `index`, without changing any other
+	/// element's index. Rather than shifting every tag field's bits
+	/// down to close the gap, the slot is tombstoned: it's marked in
+	/// a hidden "deleted" bitfield that `query` ANDs out of every
+	/// result word, and its bit is cleared in every tag field so
+	/// `iter_element` reports no tags for it. Call `compact` once
+	/// removals pile up to reclaim the wasted space; that's the only
+	/// thing that renumbers elements.
+	///
+	/// Panics if the index is out of bounds.
+	///
+	/// ```
+	/// use tag_vec::TagVec;
+	/// use tag_vec::expressions::*;
+	///
+	/// let mut tag_vec: TagVec<String> = TagVec::new();
+	/// tag_vec.push(vec!["hello", "world"]);
+	/// tag_vec.push(vec!["hello", "rust"]);
+	///
+	/// tag_vec.remove(0);
+	///
+	/// assert_eq!(tag_vec.query(tag("hello")).collect::<Vec<_>>(), vec![1]);
+	/// assert_eq!(tag_vec.iter_element(0).count(), 0);
+	/// ```
+	pub fn remove(&mut self, index: usize) {
+		assert!(index < self.len(), "Cannot remove an element out of bounds");
+
+		for field in self.tag_fields.values_mut() {
+			field.set_unchecked(index, false);
+		}
+
+		self.deleted.set_unchecked(index, true);
+	}
+
+	/// Physically reclaims every slot tombstoned by `remove`,
+	/// shrinking every tag field and renumbering the remaining
+	/// elements to be contiguous again starting from 0. Unlike
+	/// `remove`, this does change element indices.
+	///
+	/// ```
+	/// use tag_vec::TagVec;
+	/// use tag_vec::expressions::*;
+	///
+	/// let mut tag_vec: TagVec<String> = TagVec::new();
+	/// tag_vec.push(vec!["hello", "world"]);
+	/// tag_vec.push(vec!["hello", "rust"]);
+	/// tag_vec.remove(0);
+	///
+	/// tag_vec.compact();
+	///
+	/// assert_eq!(tag_vec.len(), 1);
+	/// assert_eq!(tag_vec.query(tag("hello")).collect::<Vec<_>>(), vec![0]);
+	/// ```
+	pub fn compact(&mut self) {
+		let kept: Vec<usize> = (0..self.len)
+				.filter(|&i| !self.deleted.get_unchecked(i))
+				.collect();
+
+		let mut new_tag_fields = HashMap::with_capacity(self.tag_fields.len());
+		for (tag, field) in self.tag_fields.iter() {
+			let mut new_field = TagStorage::with_false(0);
+			for &i in &kept {
+				new_field.push(field.get_unchecked(i));
+			}
+			new_tag_fields.insert(tag.clone(), new_field);
+		}
+
+		self.tag_fields = new_tag_fields;
+		self.deleted = DynamicBitField::with_false(kept.len());
+		self.len = kept.len();
+	}
+
 	/// Iterates over all elements who fulfill the given expression.
 	/// The behind the scenes of this function are complete and utter
 	/// black magic code, and that code is indeed very strange.
@@ -121,12 +205,37 @@ impl<T: Eq + Hash + Clone, F: BitField> TagVec<T, F> {
 	/// assert_eq!(query.next(), Some(1)); 
 	/// assert_eq!(query.next(), None);
 	/// ```
-	pub fn query<'a, Q>(&'a self, expr: query::Expression<'a, Q>) -> query::Query<'a, F>  
+	pub fn query<'a, Q>(&'a self, expr: query::Expression<'a, Q>) -> query::Query<'a, F>
 			where Q: ?Sized + Hash + Eq + 'a,
 					T: std::borrow::Borrow<Q> {
 		query::Query::create_from(self, expr)
 	}
 
+	/// Returns how many elements fulfill the given expression, without
+	/// materializing each matching index. This is the "how many items
+	/// match this filter?" operation, and is dramatically cheaper than
+	/// `query(expr).count()` since it sums set bits one word at a time
+	/// instead of testing and yielding every individual match.
+	///
+	/// ```
+	/// use tag_vec::TagVec;
+	/// use tag_vec::expressions::*;
+	///
+	/// let mut tag_vec: TagVec<String> = TagVec::new();
+	/// tag_vec.push(vec!["hello", "world"]);
+	/// tag_vec.push(vec!["rust", "is", "good"]);
+	/// tag_vec.push(vec!["hello", "is", "good"]);
+	/// tag_vec.push(vec!["hello", "rust"]);
+	///
+	/// assert_eq!(tag_vec.count(tag("hello")), 3);
+	/// assert_eq!(tag_vec.count(and(tag("rust"), tag("good"))), 1);
+	/// ```
+	pub fn count<'a, Q>(&'a self, expr: query::Expression<'a, Q>) -> usize
+			where Q: ?Sized + Hash + Eq + 'a,
+					T: std::borrow::Borrow<Q> {
+		query::Query::create_from(self, expr).count_matches()
+	}
+
 	/// Iterates over each tag of an element(an element is considered
 	/// to _be_ its tags). The iterator is unordered, so be careful.
 	/// Will panic if the index is out of bounds.
@@ -155,6 +264,140 @@ impl<T: Eq + Hash + Clone, F: BitField> TagVec<T, F> {
 			element_id: index
 		}
 	}
+
+	/// Serializes this TagVec into a compact, self-describing byte
+	/// stream: a header (element count, tag count), the tombstone
+	/// bitfield left by any `remove` calls, then, for each tag in turn,
+	/// its dictionary entry (the tag's byte length and bytes) directly
+	/// followed by its raw bitfield words. There is no separate
+	/// dictionary section: tag entries are interleaved with the tags'
+	/// words. `T` doesn't need to
+	/// implement any particular (de)serialization trait; instead the
+	/// caller supplies `write_tag` to encode a single tag's bytes.
+	/// Pair this with `deserialize` and its inverse closure to
+	/// round-trip a TagVec without forcing a serde dependency on `T`.
+	///
+	/// ```
+	/// use tag_vec::TagVec;
+	/// use tag_vec::expressions::*;
+	///
+	/// let mut tag_vec: TagVec<String> = TagVec::new();
+	/// tag_vec.push(vec!["hello", "world"]);
+	/// tag_vec.push(vec!["rust", "is", "good"]);
+	/// tag_vec.push(vec!["hello", "is", "good"]);
+	///
+	/// let bytes = tag_vec.serialize(|tag, out| out.extend_from_slice(tag.as_bytes()));
+	///
+	/// let restored: TagVec<String> = TagVec::deserialize(&bytes, |bytes| {
+	/// 	String::from_utf8(bytes.to_vec()).unwrap()
+	/// });
+	///
+	/// assert_eq!(restored.len(), tag_vec.len());
+	/// assert_eq!(restored.query(tag("hello")).collect::<Vec<_>>(), vec![0, 2]);
+	/// ```
+	pub fn serialize(&self, mut write_tag: impl FnMut(&T, &mut Vec<u8>)) -> Vec<u8> {
+		let mut out = Vec::new();
+
+		out.extend_from_slice(&(self.len as u64).to_le_bytes());
+		out.extend_from_slice(&(self.tag_fields.len() as u64).to_le_bytes());
+
+		let n_words = n_words_for::<F>(self.len);
+
+		// The "deleted" tombstone field is written the same way a
+		// tag's words are, just without a dictionary entry, since
+		// every TagVec has exactly one.
+		for data_index in 0..n_words {
+			self.deleted.word_at(data_index).write_le_bytes(&mut out);
+		}
+
+		for (tag, field) in self.tag_fields.iter() {
+			// Tags are prefixed with their own byte length so that
+			// `deserialize` can hand `read_tag` exactly the bytes
+			// `write_tag` produced, without needing T: Serialize.
+			let tag_start = out.len();
+			out.extend_from_slice(&0u64.to_le_bytes());
+			write_tag(tag, &mut out);
+			let tag_byte_len = (out.len() - tag_start - 8) as u64;
+			out[tag_start..tag_start + 8].copy_from_slice(&tag_byte_len.to_le_bytes());
+
+			for data_index in 0..n_words {
+				field.word_at(data_index).write_le_bytes(&mut out);
+			}
+		}
+
+		out
+	}
+
+	/// Deserializes a TagVec previously produced by `serialize`.
+	/// `read_tag` must be the exact inverse of the `write_tag` closure
+	/// passed to `serialize`: given the bytes written for one tag, it
+	/// parses and returns that tag.
+	///
+	/// Every tag's bits come back padded to `ceil(len / F::n_bits())`
+	/// words with any bits past `len` zero, matching what `push`
+	/// produces, so queries behave identically after the round trip.
+	/// Each tag is also rebuilt through the same `TagStorage::push`
+	/// promotion logic `compact` uses rather than assumed dense, so a
+	/// tag that was rare (and sparse) before serializing comes back
+	/// sparse too, instead of every tag silently densifying.
+	pub fn deserialize(bytes: &[u8], mut read_tag: impl FnMut(&[u8]) -> T) -> TagVec<T, F> {
+		let mut cursor = 0;
+
+		let len = read_u64(bytes, &mut cursor) as usize;
+		let n_tags = read_u64(bytes, &mut cursor) as usize;
+		let n_words = n_words_for::<F>(len);
+
+		let mut deleted_data = Vec::with_capacity(n_words);
+		for _ in 0..n_words {
+			let width = F::byte_width();
+			deleted_data.push(F::read_le_bytes(&bytes[cursor..cursor + width]));
+			cursor += width;
+		}
+		let deleted = DynamicBitField::from_raw(deleted_data, len);
+
+		let mut tag_fields = HashMap::with_capacity(n_tags);
+
+		for _ in 0..n_tags {
+			let tag_byte_len = read_u64(bytes, &mut cursor) as usize;
+			let tag = read_tag(&bytes[cursor..cursor + tag_byte_len]);
+			cursor += tag_byte_len;
+
+			let mut data = Vec::with_capacity(n_words);
+			for _ in 0..n_words {
+				let width = F::byte_width();
+				data.push(F::read_le_bytes(&bytes[cursor..cursor + width]));
+				cursor += width;
+			}
+
+			// Replay the bits through `TagStorage::push` rather than
+			// assuming every tag is dense: since density is a pure
+			// function of which bits are set, a tag that was sparse
+			// before serializing ends up sparse again here too.
+			let raw = DynamicBitField::from_raw(data, len);
+			let mut storage = TagStorage::with_false(0);
+			for i in 0..len {
+				storage.push(raw.get_unchecked(i));
+			}
+
+			tag_fields.insert(tag, storage);
+		}
+
+		TagVec { tag_fields, deleted, len }
+	}
+}
+
+/// The number of `F` words needed to hold `len` bits, rounded up.
+fn n_words_for<F: BitField>(len: usize) -> usize {
+	let n_bits = F::n_bits();
+	(len + n_bits - 1) / n_bits
+}
+
+/// Reads a little-endian `u64` from `bytes` at `*cursor`, advancing
+/// `*cursor` past it.
+fn read_u64(bytes: &[u8], cursor: &mut usize) -> u64 {
+	let value = u64::from_le_bytes(bytes[*cursor..*cursor + 8].try_into().unwrap());
+	*cursor += 8;
+	value
 }
 
 /// Iterates over every tag over an element.
@@ -163,7 +406,7 @@ impl<T: Eq + Hash + Clone, F: BitField> TagVec<T, F> {
 #[derive(Clone)]
 pub struct IterElement<'a, T, F>
 		where T: Eq + Hash + Clone, F: BitField {
-	fields: std::collections::hash_map::Iter<'a, T, DynamicBitField<F>>,
+	fields: std::collections::hash_map::Iter<'a, T, TagStorage<F>>,
 	element_id: usize,
 }
 
@@ -238,4 +481,133 @@ mod tests {
 		assert_eq!(contains[1], 12);
 		assert_eq!(contains[2], 19);
 	}
+
+	#[test]
+	fn removal_tombstones_without_shifting_indices() {
+		use super::expressions::*;
+
+		let mut tags = TagVec::<String>::new();
+		tags.push(vec!["hello", "world"]);
+		tags.push(vec!["hello", "rust"]);
+		tags.push(vec!["hello"]);
+
+		tags.remove(1);
+
+		// The index of the untouched elements doesn't move
+		assert_eq!(tags.len(), 3);
+		assert_eq!(tags.query(tag("hello")).collect::<Vec<_>>(), vec![0, 2]);
+		assert_eq!(tags.count(tag("hello")), 2);
+		assert_eq!(tags.iter_element(1).count(), 0);
+	}
+
+	#[test]
+	fn compact_reclaims_tombstones_and_renumbers() {
+		use super::expressions::*;
+
+		let mut tags = TagVec::<String>::new();
+		tags.push(vec!["hello", "world"]);
+		tags.push(vec!["hello", "rust"]);
+		tags.push(vec!["hello"]);
+
+		tags.remove(1);
+		tags.compact();
+
+		assert_eq!(tags.len(), 2);
+		assert_eq!(tags.query(tag("hello")).collect::<Vec<_>>(), vec![0, 1]);
+		assert!(!tags.iter_element(0).any(|v| v == "rust"));
+		assert!(!tags.iter_element(1).any(|v| v == "rust"));
+	}
+
+	#[test]
+	fn serialize_round_trip_spans_multiple_words() {
+		// u8 words are 8 bits wide, so 20 elements need 3 words per tag
+		let mut tags = TagVec::<String, u8>::new();
+		for i in 0..20 {
+			if i % 2 == 0 {
+				tags.push(vec!["even"]);
+			} else {
+				tags.push(vec!["odd"]);
+			}
+		}
+
+		let bytes = tags.serialize(|tag, out| out.extend_from_slice(tag.as_bytes()));
+		let restored: TagVec<String, u8> = TagVec::deserialize(&bytes, |bytes| {
+			String::from_utf8(bytes.to_vec()).unwrap()
+		});
+
+		use super::expressions::*;
+		assert_eq!(restored.len(), tags.len());
+		assert_eq!(restored.query(tag("even")).collect::<Vec<_>>(), tags.query(tag("even")).collect::<Vec<_>>());
+		assert_eq!(restored.query(tag("odd")).collect::<Vec<_>>(), tags.query(tag("odd")).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn serialize_round_trip_handles_empty_tag_vec() {
+		let tags = TagVec::<String>::new();
+		let bytes = tags.serialize(|tag, out| out.extend_from_slice(tag.as_bytes()));
+		let restored: TagVec<String> = TagVec::deserialize(&bytes, |bytes| {
+			String::from_utf8(bytes.to_vec()).unwrap()
+		});
+
+		assert_eq!(restored.len(), 0);
+	}
+
+	#[test]
+	fn serialize_round_trip_handles_zero_tags() {
+		// Elements with no tags at all still occupy slots and should
+		// round-trip even though there's nothing in the dictionary.
+		let mut tags = TagVec::<String>::new();
+		tags.push(Vec::<&str>::new());
+		tags.push(Vec::<&str>::new());
+
+		let bytes = tags.serialize(|tag, out| out.extend_from_slice(tag.as_bytes()));
+		let restored: TagVec<String> = TagVec::deserialize(&bytes, |bytes| {
+			String::from_utf8(bytes.to_vec()).unwrap()
+		});
+
+		assert_eq!(restored.len(), 2);
+	}
+
+	#[test]
+	fn serialize_preserves_deleted_tombstones() {
+		use super::expressions::*;
+
+		let mut tags = TagVec::<String>::new();
+		tags.push(vec!["hello", "world"]);
+		tags.push(vec!["hello", "rust"]);
+		tags.remove(0);
+
+		let bytes = tags.serialize(|tag, out| out.extend_from_slice(tag.as_bytes()));
+		let restored: TagVec<String> = TagVec::deserialize(&bytes, |bytes| {
+			String::from_utf8(bytes.to_vec()).unwrap()
+		});
+
+		assert_eq!(restored.query(tag("hello")).collect::<Vec<_>>(), vec![1]);
+	}
+
+	#[test]
+	fn serialize_round_trip_preserves_sparse_tag_representation() {
+		// A tag held by only a handful of a large vec stays backed by
+		// a `SparseBitField`; a round trip shouldn't force it dense.
+		let mut tags = TagVec::<String, u64>::new();
+		for i in 0..5000 {
+			if i == 0 {
+				tags.push(vec!["rare"]);
+			} else {
+				tags.push(Vec::<&str>::new());
+			}
+		}
+
+		assert!(matches!(tags.tag_fields["rare"], TagStorage::Sparse(_)));
+
+		let bytes = tags.serialize(|tag, out| out.extend_from_slice(tag.as_bytes()));
+		let restored: TagVec<String, u64> = TagVec::deserialize(&bytes, |bytes| {
+			String::from_utf8(bytes.to_vec()).unwrap()
+		});
+
+		assert!(matches!(restored.tag_fields["rare"], TagStorage::Sparse(_)));
+
+		use super::expressions::*;
+		assert_eq!(restored.query(tag("rare")).collect::<Vec<_>>(), vec![0]);
+	}
 }
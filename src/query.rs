@@ -1,5 +1,8 @@
 use crate::TagVec;
 use crate::BitField;
+use crate::dyn_bit_field::DynamicBitField;
+use crate::tag_storage::TagStorage;
+use std::collections::HashMap;
 use std::hash::Hash;
 
 /// Conveniences for creating expressions easily.
@@ -42,6 +45,15 @@ pub enum Expression<'a, Q> where Q: ?Sized + Hash + Eq + 'a {
 	Or(Box<Expression<'a, Q>>, Box<Expression<'a, Q>>),
 	Not(Box<Expression<'a, Q>>),
 	Tag(&'a Q),
+	/// Matches nothing. Mainly produced by `into_dnf`, when every
+	/// clause it found turned out to be a contradiction.
+	Empty,
+	/// Matches everything. The De Morgan partner of `Empty`: `push_not`
+	/// turns a negated `Empty` into this and a negated `All` back into
+	/// `Empty`, so unlike a plain "leave it as-is" shortcut, negating
+	/// either is sound no matter how deeply it's nested or who
+	/// constructed it.
+	All,
 }
 
 impl<'a, Q: ?Sized + Hash + Eq + 'a> Expression<'a, Q> {
@@ -55,6 +67,9 @@ impl<'a, Q: ?Sized + Hash + Eq + 'a> Expression<'a, Q> {
 			Or(a, b) => 1 + a.command_size() + b.command_size(),
 			Not(a) => 1 + a.command_size(),
 			Tag(_) => 1,
+			Empty => 1,
+			// Reused to_commands below renders this as Not(Empty).
+			All => 2,
 		}
 	}
 
@@ -85,8 +100,208 @@ impl<'a, Q: ?Sized + Hash + Eq + 'a> Expression<'a, Q> {
 				// a get tag command.
 				commands.push(id);
 			},
+			Empty => commands.push(QUERY_CMD_EMPTY),
+			All => {
+				// "Matches everything" is just the real negation of
+				// "matches nothing" - reuse the command stack's actual
+				// NOT instead of adding a dedicated command for it.
+				commands.push(QUERY_CMD_EMPTY);
+				commands.push(QUERY_CMD_NOT);
+			},
+		}
+	}
+
+	/// Rewrites this expression into disjunctive normal form: an Or of
+	/// And-clauses, each a conjunction of possibly-negated tags, as the
+	/// command stack evaluates most efficiently. `Not` is pushed down to
+	/// the leaves via De Morgan's laws, then `And` is distributed over
+	/// `Or`. Within each resulting clause, repeated tags are deduplicated
+	/// and a tag appearing both negated and non-negated collapses the
+	/// clause to nothing (it can never match); across clauses, a clause
+	/// that's subsumed by a strictly smaller one is dropped, since
+	/// anything satisfying the smaller clause already satisfies the Or.
+	pub fn into_dnf(self) -> Expression<'a, Q> {
+		let nnf = self.push_not(false);
+		let clauses = dedup_clauses(distribute(nnf));
+		let clauses = drop_subsumed(clauses);
+
+		let mut clauses = clauses.into_iter();
+		match clauses.next() {
+			None => Expression::Empty,
+			Some(first) => clauses.fold(clause_to_expr(first), |acc, clause| {
+				Expression::Or(Box::new(acc), Box::new(clause_to_expr(clause)))
+			}),
+		}
+	}
+
+	/// Pushes negation down to the leaves via De Morgan's laws, so that
+	/// the only `Not`s left in the result wrap a bare `Tag`. `negate`
+	/// tracks whether an odd number of `Not`s are still pending above
+	/// this node.
+	fn push_not(self, negate: bool) -> Expression<'a, Q> {
+		use Expression::*;
+
+		match self {
+			And(a, b) => {
+				let a = a.push_not(negate);
+				let b = b.push_not(negate);
+				if negate {
+					Or(Box::new(a), Box::new(b))
+				} else {
+					And(Box::new(a), Box::new(b))
+				}
+			},
+			Or(a, b) => {
+				let a = a.push_not(negate);
+				let b = b.push_not(negate);
+				if negate {
+					And(Box::new(a), Box::new(b))
+				} else {
+					Or(Box::new(a), Box::new(b))
+				}
+			},
+			Not(a) => a.push_not(!negate),
+			Tag(tag) => if negate { Not(Box::new(Tag(tag))) } else { Tag(tag) },
+			Empty => if negate { All } else { Empty },
+			All => if negate { Empty } else { All },
+		}
+	}
+}
+
+/// A single And-clause of a DNF expression: a map from tag to whether
+/// it's negated in this clause. A `HashMap` doubles as both the
+/// dedup (repeated tags collapse to one entry) and contradiction check
+/// (the same tag can't be stored as both negated and non-negated).
+type Clause<'a, Q> = HashMap<&'a Q, bool>;
+
+/// Distributes `And` over `Or` in a negation-normal-form expression
+/// (i.e. one already run through `push_not`), producing the clause list
+/// of its DNF. Clauses that turn out contradictory are dropped here.
+fn distribute<'a, Q: ?Sized + Hash + Eq + 'a>(expr: Expression<'a, Q>) -> Vec<Clause<'a, Q>> {
+	use Expression::*;
+
+	match expr {
+		Or(a, b) => {
+			let mut clauses = distribute(*a);
+			clauses.extend(distribute(*b));
+			clauses
+		},
+		And(a, b) => {
+			let left = distribute(*a);
+			let right = distribute(*b);
+			let mut out = Vec::with_capacity(left.len() * right.len());
+
+			for lc in &left {
+				for rc in right.iter() {
+					if let Some(merged) = merge_clause(lc, rc) {
+						out.push(merged);
+					}
+				}
+			}
+
+			out
+		},
+		Not(tag) => match *tag {
+			Tag(tag) => vec![singleton_clause(tag, true)],
+			_ => unreachable!("push_not leaves Not wrapping only bare tags"),
+		},
+		Tag(tag) => vec![singleton_clause(tag, false)],
+		Empty => Vec::new(),
+		// The clause with no literals at all: trivially satisfied by
+		// every element, so `drop_subsumed` will collapse any `Or` it
+		// appears in down to just this clause.
+		All => vec![HashMap::new()],
+	}
+}
+
+fn singleton_clause<'a, Q: ?Sized + Hash + Eq + 'a>(tag: &'a Q, negated: bool) -> Clause<'a, Q> {
+	let mut clause = HashMap::new();
+	clause.insert(tag, negated);
+	clause
+}
+
+/// Merges two clauses' literals, returning `None` if the same tag is
+/// required both negated and non-negated (a contradiction, so the
+/// merged clause can never match and is dropped).
+fn merge_clause<'a, Q: ?Sized + Hash + Eq + 'a>(a: &Clause<'a, Q>, b: &Clause<'a, Q>) -> Option<Clause<'a, Q>> {
+	let mut merged = a.clone();
+
+	for (&tag, &negated) in b.iter() {
+		match merged.get(&tag) {
+			Some(&existing) if existing != negated => return None,
+			_ => { merged.insert(tag, negated); },
 		}
 	}
+
+	Some(merged)
+}
+
+/// Removes exact duplicate clauses, keeping the first occurrence.
+fn dedup_clauses<'a, Q: ?Sized + Hash + Eq + 'a>(clauses: Vec<Clause<'a, Q>>) -> Vec<Clause<'a, Q>> {
+	let mut kept: Vec<Clause<'a, Q>> = Vec::with_capacity(clauses.len());
+
+	for clause in clauses {
+		let is_duplicate = kept.iter().any(|k| clauses_equal(k, &clause));
+		if !is_duplicate {
+			kept.push(clause);
+		}
+	}
+
+	kept
+}
+
+/// Drops any clause that is subsumed by a strictly smaller clause, i.e.
+/// one whose literals are a strict subset of the dropped clause's. Since
+/// matching the smaller clause implies matching the larger one, keeping
+/// both in the Or is redundant.
+fn drop_subsumed<'a, Q: ?Sized + Hash + Eq + 'a>(mut clauses: Vec<Clause<'a, Q>>) -> Vec<Clause<'a, Q>> {
+	clauses.sort_by_key(|clause| clause.len());
+
+	let mut kept: Vec<Clause<'a, Q>> = Vec::with_capacity(clauses.len());
+	'clauses: for clause in clauses {
+		for smaller in &kept {
+			if smaller.len() < clause.len() && is_subset(smaller, &clause) {
+				continue 'clauses;
+			}
+		}
+
+		kept.push(clause);
+	}
+
+	kept
+}
+
+fn clauses_equal<'a, Q: ?Sized + Hash + Eq + 'a>(a: &Clause<'a, Q>, b: &Clause<'a, Q>) -> bool {
+	a.len() == b.len() && is_subset(a, b)
+}
+
+fn is_subset<'a, Q: ?Sized + Hash + Eq + 'a>(small: &Clause<'a, Q>, big: &Clause<'a, Q>) -> bool {
+	small.iter().all(|(tag, negated)| big.get(tag) == Some(negated))
+}
+
+/// Converts one DNF clause back into an `Expression` of Ands of
+/// (possibly negated) tags.
+fn clause_to_expr<'a, Q: ?Sized + Hash + Eq + 'a>(clause: Clause<'a, Q>) -> Expression<'a, Q> {
+	let mut literals = clause.into_iter();
+
+	let (first_tag, first_negated) = match literals.next() {
+		Some(literal) => literal,
+		// The clause `All` distributes to: no literals means no
+		// constraint at all, i.e. it matches everything.
+		None => return Expression::All,
+	};
+
+	literals.fold(literal_expr(first_tag, first_negated), |acc, (tag, negated)| {
+		Expression::And(Box::new(acc), Box::new(literal_expr(tag, negated)))
+	})
+}
+
+fn literal_expr<'a, Q: ?Sized + Hash + Eq + 'a>(tag: &'a Q, negated: bool) -> Expression<'a, Q> {
+	if negated {
+		Expression::Not(Box::new(Expression::Tag(tag)))
+	} else {
+		Expression::Tag(tag)
+	}
 }
 
 // Define command constants. This cannot be represented as an
@@ -94,18 +309,25 @@ impl<'a, Q: ?Sized + Hash + Eq + 'a> Expression<'a, Q> {
 const QUERY_CMD_AND: u16 = 0xFFFF;
 const QUERY_CMD_OR: u16 = 0xFFFD;
 const QUERY_CMD_NOT: u16 = 0xFFFC;
-const QUERY_CMD_TAG: u16 = 0xFFFC; // Less than, not equals
+const QUERY_CMD_EMPTY: u16 = 0xFFFB;
+const QUERY_CMD_TAG: u16 = 0xFFFB; // Less than, not equals
 
 /// A Query iterator. Will iterate over the elements of a TagVec
 /// that fulfill a requirement, defined by the "Expression" enum.
-pub struct Query<'a, F> 
-		where 
+pub struct Query<'a, F>
+		where
 				F: BitField {
-	tag_data: Vec<Option<&'a [F]>>,
+	tag_data: Vec<Option<&'a TagStorage<F>>>,
+	/// The elements `TagVec::remove` has tombstoned; ANDed, negated,
+	/// into every result word so removed elements never match.
+	deleted: &'a DynamicBitField<F>,
 	commands: Vec<u16>,
-	bit_ctr: usize,
+	/// The index of the next result word to fetch from the command
+	/// stack. The word at `data_index - 1` is the one currently
+	/// loaded into `word` (if any bits of it are still unscanned).
+	data_index: usize,
 	total_bits: usize,
-	data: F,
+	word: F,
 	stack: Vec<F>,
 }
 
@@ -126,6 +348,11 @@ impl<'a, F> Query<'a, F>
 			-> Query<'a, F> 
 				where T: Eq + Hash + Clone + std::borrow::Borrow<Q>, 
 						Q: ?Sized + Eq + Hash + 'a {
+		// Normalizing to DNF first means the command stack `to_commands`
+		// builds is often shorter (duplicate/subsumed clauses and
+		// contradictions are gone before it ever sees the expression).
+		let expr = expr.into_dnf();
+
 		let mut tag_requests = Vec::new();
 		let mut commands = Vec::with_capacity(expr.command_size());
 
@@ -134,62 +361,94 @@ impl<'a, F> Query<'a, F>
 		// Get references to the data storing the things
 		// the commands want
 		let tag_data: Vec<_> = tag_requests.into_iter()
-				.map(|request| vec.tag_fields.get(request).map(|v| v.data())).collect();
+				.map(|request| vec.tag_fields.get(request)).collect();
 
 		Query {
 			tag_data,
+			deleted: &vec.deleted,
 			commands,
-			bit_ctr: 0,
+			data_index: 0,
 			total_bits: vec.len(),
-			data: F::empty(),
+			word: F::empty(),
 			stack: Vec::new(),
 		}
 	}
 
-	/// Assumes that there is another element.
-	/// Also, only returns a bool, wether or not
-	/// the condition was fulfilled the next iteration
-	fn sloppy_next(&mut self) -> bool {
-		let local_index = self.bit_ctr % F::n_bits();
-
-		if local_index == 0 {
-			// We are on a new bit! Evaluate the local BitField first
-			let data_index = self.bit_ctr / F::n_bits();
-
-			// We assume that the stack is always sufficiently populated, because the "to_commands"
-			// function shouldn't generate commands that break this
-			self.stack.clear();
-			let stack = &mut self.stack;
-			let commands = &self.commands;
-			let tag_data = &self.tag_data;
-			for cmd in commands.iter() {
-				match *cmd {
-					QUERY_CMD_AND => {
-						let a = stack.pop().unwrap();
-						let b = stack.pop().unwrap();
-						stack.push(a & b);
-					},
-					QUERY_CMD_OR => {
-						let a = stack.pop().unwrap();
-						let b = stack.pop().unwrap();
-						stack.push(a | b);
-					},
-					QUERY_CMD_NOT => {
-						let a = stack.pop().unwrap();
-						stack.push(!a);
-					},
-					tag => {
-						// It's definitely a tag
-						stack.push(tag_data[tag as usize].map_or(F::empty(), |v| v[data_index]));
-					},
-				}
+	/// Runs the command stack for the next result word, storing it in
+	/// `self.word`. Returns false if there is no next word left to fetch,
+	/// i.e. the query is exhausted.
+	fn next_word(&mut self) -> bool {
+		let data_index = self.data_index;
+
+		if data_index * F::n_bits() >= self.total_bits {
+			return false;
+		}
+
+		// We assume that the stack is always sufficiently populated, because the "to_commands"
+		// function shouldn't generate commands that break this
+		self.stack.clear();
+		let stack = &mut self.stack;
+		let commands = &self.commands;
+		let tag_data = &self.tag_data;
+		for cmd in commands.iter() {
+			match *cmd {
+				QUERY_CMD_AND => {
+					let a = stack.pop().unwrap();
+					let b = stack.pop().unwrap();
+					stack.push(a & b);
+				},
+				QUERY_CMD_OR => {
+					let a = stack.pop().unwrap();
+					let b = stack.pop().unwrap();
+					stack.push(a | b);
+				},
+				QUERY_CMD_NOT => {
+					let a = stack.pop().unwrap();
+					stack.push(!a);
+				},
+				QUERY_CMD_EMPTY => stack.push(F::empty()),
+				tag => {
+					// It's definitely a tag
+					stack.push(tag_data[tag as usize].map_or(F::empty(), |v| v.word_at(data_index)));
+				},
 			}
+		}
 
-			self.data = stack[0];
+		// Mask out any elements `remove` has tombstoned, rather than
+		// making every tag field physically shift its bits to close
+		// the gap.
+		let mut word = stack[0] & !self.deleted.word_at(data_index);
+
+		// Mask off any bits past total_bits in the final, possibly
+		// partial, word so we never yield an out-of-range index. Bits at
+		// or above `F::n_bits()` hold no element but can be set by a NOT,
+		// so they are cleared in every word too.
+		let word_start = data_index * F::n_bits();
+		let valid_bits = std::cmp::min(self.total_bits - word_start, F::n_bits());
+		for n in valid_bits..(F::byte_width() * 8) {
+			word.set_bit(n, false);
 		}
 
-		self.bit_ctr += 1;
-		self.data.get_bit(local_index)
+		self.word = word;
+		self.data_index += 1;
+		true
+	}
+
+	/// Counts how many elements fulfill this query's expression, without
+	/// materializing each matching index. This runs the same command
+	/// stack as iteration, but sums `F::count_ones()` across the result
+	/// words instead of scanning individual bits, so it stays cheap even
+	/// when the query matches almost everything.
+	pub(crate) fn count_matches(&mut self) -> usize {
+		self.data_index = 0;
+		self.word = F::empty();
+
+		let mut count = 0;
+		while self.next_word() {
+			count += self.word.count_ones() as usize;
+		}
+
+		count
 	}
 }
 
@@ -197,14 +456,147 @@ impl<'a, F> Iterator for Query<'a, F> where F: BitField {
 	type Item = usize;
 
 	fn next(&mut self) -> Option<usize> {
-		while self.bit_ctr < self.total_bits {
-			if self.sloppy_next() {
-				// The bit_ctr has been increased in sloppy_next, so 
-				// we have to return the previous one
-				return Some(self.bit_ctr - 1);
+		loop {
+			if self.word.is_zero() {
+				if !self.next_word() {
+					return None;
+				}
+
+				continue;
 			}
+
+			// Scan the set bits of the current word one at a time,
+			// rather than testing every bit, so sparse matches cost
+			// roughly one step per match instead of one per element.
+			let tz = self.word.trailing_zeros() as usize;
+			let data_index = self.data_index - 1;
+			self.word.set_bit(tz, false);
+
+			return Some(data_index * F::n_bits() + tz);
 		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::TagVec;
+
+	/// Runs an expression through `to_commands` and returns the
+	/// `(tag_requests, commands)` pair, so `into_dnf`'s output can be
+	/// asserted on without `Expression` needing `PartialEq`/`Debug`.
+	fn commands_for<'a>(expr: Expression<'a, str>) -> (Vec<&'a str>, Vec<u16>) {
+		let mut tag_requests = Vec::new();
+		let mut commands = Vec::new();
+		expr.to_commands(&mut tag_requests, &mut commands);
+		(tag_requests, commands)
+	}
+
+	#[test]
+	fn push_not_distributes_negation_via_de_morgan() {
+		let expr: Expression<str> = Expression::Not(Box::new(Expression::And(
+			Box::new(Expression::Tag("a")),
+			Box::new(Expression::Tag("b")),
+		)));
+
+		// Not(And(a, b)) -> Or(Not(a), Not(b))
+		let (tag_requests, commands) = commands_for(expr.push_not(false));
+		assert_eq!(tag_requests, vec!["a", "b"]);
+		assert_eq!(commands, vec![0, QUERY_CMD_NOT, 1, QUERY_CMD_NOT, QUERY_CMD_OR]);
+	}
+
+	#[test]
+	fn contradiction_collapses_clause_to_empty() {
+		let expr: Expression<str> = Expression::And(
+			Box::new(Expression::Tag("a")),
+			Box::new(Expression::Not(Box::new(Expression::Tag("a")))),
+		);
+
+		let (tag_requests, commands) = commands_for(expr.into_dnf());
+		assert!(tag_requests.is_empty());
+		assert_eq!(commands, vec![QUERY_CMD_EMPTY]);
+	}
+
+	#[test]
+	fn subsumed_clause_is_dropped() {
+		// (a) OR (a AND b): anything satisfying the smaller clause "a"
+		// already satisfies the Or, so "a AND b" is redundant.
+		let expr: Expression<str> = Expression::Or(
+			Box::new(Expression::Tag("a")),
+			Box::new(Expression::And(Box::new(Expression::Tag("a")), Box::new(Expression::Tag("b")))),
+		);
+
+		let (tag_requests, commands) = commands_for(expr.into_dnf());
+		assert_eq!(tag_requests, vec!["a"]);
+		assert_eq!(commands, vec![0]);
+	}
+
+	#[test]
+	fn duplicate_clause_is_deduped() {
+		let expr: Expression<str> = Expression::Or(
+			Box::new(Expression::Tag("a")),
+			Box::new(Expression::Tag("a")),
+		);
+
+		let (tag_requests, commands) = commands_for(expr.into_dnf());
+		assert_eq!(tag_requests, vec!["a"]);
+		assert_eq!(commands, vec![0]);
+	}
+
+	#[test]
+	fn not_of_empty_matches_everything() {
+		// Not(Empty) should mean "matches everything", per De Morgan's
+		// laws, not "still matches nothing".
+		let expr: Expression<str> = Expression::Not(Box::new(Expression::Empty));
+
+		let (tag_requests, commands) = commands_for(expr.into_dnf());
+		assert!(tag_requests.is_empty());
+		assert_eq!(commands, vec![QUERY_CMD_EMPTY, QUERY_CMD_NOT]);
+	}
+
+	#[test]
+	fn not_of_all_matches_nothing() {
+		let expr: Expression<str> = Expression::Not(Box::new(Expression::All));
+
+		let (tag_requests, commands) = commands_for(expr.into_dnf());
+		assert!(tag_requests.is_empty());
+		assert_eq!(commands, vec![QUERY_CMD_EMPTY]);
+	}
+
+	#[test]
+	fn double_negated_empty_through_dnf_round_trip_stays_sound() {
+		// A realistic caller pattern: normalize, then later wrap the
+		// normalized (possibly Empty) result in `not` and query again.
+		// `normalized` matches nothing, so negating it should normalize
+		// to something that matches everything, not stay Empty.
+		let contradiction: Expression<str> = Expression::And(
+			Box::new(Expression::Tag("a")),
+			Box::new(Expression::Not(Box::new(Expression::Tag("a")))),
+		);
+		let normalized = contradiction.into_dnf();
+
+		let (tag_requests, commands) = commands_for(Expression::Not(Box::new(normalized)).into_dnf());
+		assert!(tag_requests.is_empty());
+		assert_eq!(commands, vec![QUERY_CMD_EMPTY, QUERY_CMD_NOT]);
+	}
+
+	#[test]
+	fn mixed_tree_normalizes_and_evaluates_correctly() {
+		let mut tags = TagVec::<String>::new();
+		tags.push(vec!["a", "b"]); // 0: a and b
+		tags.push(vec!["a"]);      // 1: a only
+		tags.push(vec!["b"]);      // 2: b only
+		tags.push(vec!["c"]);      // 3: c only
+
+		// (a AND NOT b) OR c matches elements 1 and 3
+		let expr = Expression::Or(
+			Box::new(Expression::And(
+				Box::new(Expression::Tag("a")),
+				Box::new(Expression::Not(Box::new(Expression::Tag("b")))),
+			)),
+			Box::new(Expression::Tag("c")),
+		);
 
-		None
+		assert_eq!(tags.query(expr).collect::<Vec<_>>(), vec![1, 3]);
 	}
 }
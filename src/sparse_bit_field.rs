@@ -0,0 +1,332 @@
+use crate::bit_field::BitField;
+
+/// Number of bits in each independently-represented chunk.
+const CHUNK_BITS: usize = 65536;
+
+/// A single chunk's worth of bits: either dense words, or a sorted
+/// list of the chunk-local offsets of its set bits, whichever is
+/// smaller.
+enum Chunk<T: BitField> {
+	Dense(Vec<T>),
+	Sparse(Vec<u32>),
+}
+
+/// A `DynamicBitField` alternative for tags that are set on only a
+/// small fraction of elements, where a dense `ceil(len / n_bits)`-word
+/// array would be mostly zeroes. Bits are partitioned into fixed-size
+/// chunks of `CHUNK_BITS` elements; each chunk independently stores
+/// itself as dense words or as a sorted offset list, switching to
+/// dense the moment the offset list would take at least as much space.
+/// `word_at`
+/// lets `Query` fetch one word at a time the same way it would from a
+/// dense `DynamicBitField`, synthesizing it from the offset list when
+/// the containing chunk is still sparse.
+pub(crate) struct SparseBitField<T: BitField> {
+	chunks: Vec<Chunk<T>>,
+	len: usize,
+	set_count: usize,
+}
+
+impl<T: BitField> SparseBitField<T> {
+	fn words_per_chunk() -> usize {
+		CHUNK_BITS / T::n_bits()
+	}
+
+	/// Creates a SparseBitField with `n_bits` bits set to false.
+	pub(crate) fn with_false(n_bits: usize) -> SparseBitField<T> {
+		let n_chunks = 1 + n_bits / CHUNK_BITS;
+
+		SparseBitField {
+			chunks: (0..n_chunks).map(|_| Chunk::Sparse(Vec::new())).collect(),
+			len: n_bits,
+			set_count: 0,
+		}
+	}
+
+	/// Returns the length in bits of the SparseBitField
+	pub(crate) fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Pushes a bit onto the SparseBitField.
+	/// Panics if the size overflows usize
+	pub(crate) fn push(&mut self, value: bool) {
+		assert!(self.len < std::usize::MAX);
+
+		let chunk_index = self.len / CHUNK_BITS;
+		let local_bit = self.len % CHUNK_BITS;
+		self.len += 1;
+
+		if self.chunks.len() <= chunk_index {
+			self.chunks.push(Chunk::Sparse(Vec::new()));
+		}
+
+		// A bit that isn't set needs no storage: absence from a sparse
+		// chunk's offset list already means false, and a dense chunk
+		// starts every word at `T::empty()`.
+		if !value {
+			return;
+		}
+
+		self.set_count += 1;
+
+		match &mut self.chunks[chunk_index] {
+			Chunk::Dense(words) => {
+				let (word_index, bit_index) = (local_bit / T::n_bits(), local_bit % T::n_bits());
+				words[word_index].set_bit(bit_index, true);
+			},
+			Chunk::Sparse(offsets) => offsets.push(local_bit as u32),
+		}
+
+		self.densify_if_smaller(chunk_index);
+	}
+
+	/// Returns true once this field as a whole would be no larger stored
+	/// as a single dense word array than as a list of set-bit offsets
+	/// (one `u32` per set bit). `TagStorage` uses this to decide when a
+	/// tag has stopped being rare and should be promoted out of
+	/// `SparseBitField` entirely.
+	///
+	/// The denominator is the dense-word capacity of every chunk
+	/// currently allocated, not a word count derived from `self.len`:
+	/// comparing against the elapsed length lets a handful of early set
+	/// bits look "dense enough" long before a tag's steady-state
+	/// density is known (since `len` only grows, a tag that's genuinely
+	/// rare over its full lifetime can still look dense while it's
+	/// short), which would promote it out of `Sparse` permanently on
+	/// the strength of early noise alone. Using the full capacity of
+	/// the chunks that exist so far matches the same stable threshold
+	/// `densify_if_smaller` already uses per chunk.
+	pub(crate) fn is_dense_enough(&self) -> bool {
+		let dense_bytes = self.chunks.len() * Self::words_per_chunk() * T::byte_width();
+		let sparse_bytes = self.set_count * std::mem::size_of::<u32>();
+
+		sparse_bytes >= dense_bytes
+	}
+
+	/// Converts a chunk from a sorted offset list to dense words once
+	/// the offset list would use at least as much memory as the dense
+	/// words would.
+	fn densify_if_smaller(&mut self, chunk_index: usize) {
+		let words_per_chunk = Self::words_per_chunk();
+		let dense_bytes = words_per_chunk * T::byte_width();
+
+		let offsets = match &self.chunks[chunk_index] {
+			Chunk::Sparse(offsets) => offsets,
+			Chunk::Dense(_) => return,
+		};
+
+		let sparse_bytes = offsets.len() * std::mem::size_of::<u32>();
+		if sparse_bytes < dense_bytes {
+			return;
+		}
+
+		let mut words: Vec<T> = (0..words_per_chunk).map(|_| T::empty()).collect();
+		for &offset in offsets {
+			let offset = offset as usize;
+			let (word_index, bit_index) = (offset / T::n_bits(), offset % T::n_bits());
+			words[word_index].set_bit(bit_index, true);
+		}
+
+		self.chunks[chunk_index] = Chunk::Dense(words);
+	}
+
+	/// Returns the `data_index`'th word, i.e. the same word a dense
+	/// `DynamicBitField`'s `word_at(data_index)` would give,
+	/// synthesizing it from the offset list if its chunk is sparse.
+	pub(crate) fn word_at(&self, data_index: usize) -> T {
+		let words_per_chunk = Self::words_per_chunk();
+		let chunk_index = data_index / words_per_chunk;
+		let local_word_index = data_index % words_per_chunk;
+
+		match self.chunks.get(chunk_index) {
+			None => T::empty(),
+			Some(Chunk::Dense(words)) => {
+				if local_word_index < words.len() {
+					words[local_word_index]
+				} else {
+					T::empty()
+				}
+			},
+			Some(Chunk::Sparse(offsets)) => {
+				let word_start = local_word_index * T::n_bits();
+				let word_end = word_start + T::n_bits();
+
+				// `offsets` is sorted, so the offsets belonging to this
+				// word are a contiguous slice - binary search its
+				// bounds instead of scanning every offset in the chunk.
+				let lo = offsets.partition_point(|&o| (o as usize) < word_start);
+				let hi = offsets.partition_point(|&o| (o as usize) < word_end);
+
+				let mut word = T::empty();
+				for &offset in &offsets[lo..hi] {
+					word.set_bit(offset as usize - word_start, true);
+				}
+
+				word
+			},
+		}
+	}
+
+	/// Returns a value at the index.
+	/// Panics if the index is out of bounds
+	pub(crate) fn get_unchecked(&self, index: usize) -> bool {
+		let chunk_index = index / CHUNK_BITS;
+		let local_bit = index % CHUNK_BITS;
+
+		match &self.chunks[chunk_index] {
+			Chunk::Dense(words) => {
+				let (word_index, bit_index) = (local_bit / T::n_bits(), local_bit % T::n_bits());
+				words[word_index].get_bit(bit_index)
+			},
+			Chunk::Sparse(offsets) => offsets.binary_search(&(local_bit as u32)).is_ok(),
+		}
+	}
+
+	/// Sets the bit at `index` to `value`, overwriting whatever was
+	/// there before and keeping `set_count` in sync so
+	/// `is_dense_enough` stays accurate.
+	/// Panics if the index is out of bounds
+	pub(crate) fn set_unchecked(&mut self, index: usize, value: bool) {
+		let chunk_index = index / CHUNK_BITS;
+		let local_bit = index % CHUNK_BITS;
+
+		if self.get_unchecked(index) == value {
+			return;
+		}
+
+		match &mut self.chunks[chunk_index] {
+			Chunk::Dense(words) => {
+				let (word_index, bit_index) = (local_bit / T::n_bits(), local_bit % T::n_bits());
+				words[word_index].set_bit(bit_index, value);
+			},
+			Chunk::Sparse(offsets) => {
+				// Keep `offsets` sorted (unlike `push`, which only ever
+				// appends in increasing order) so `word_at` and
+				// `get_unchecked` can binary search it.
+				let pos = offsets.binary_search(&(local_bit as u32));
+				if value {
+					if let Err(pos) = pos {
+						offsets.insert(pos, local_bit as u32);
+					}
+				} else if let Ok(pos) = pos {
+					offsets.remove(pos);
+				}
+			},
+		}
+
+		if value {
+			self.set_count += 1;
+			self.densify_if_smaller(chunk_index);
+		} else {
+			self.set_count -= 1;
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn sparse_field() {
+		let mut field = SparseBitField::<u32>::with_false(0);
+		field.push(true);
+		field.push(false);
+		field.push(true);
+
+		assert_eq!(field.len(), 3);
+		assert_eq!(field.get_unchecked(0), true);
+		assert_eq!(field.get_unchecked(1), false);
+		assert_eq!(field.get_unchecked(2), true);
+
+		for _ in 3..101 {
+			field.push(false);
+		}
+		field.push(true);
+
+		assert_eq!(field.get_unchecked(101), true);
+		assert_eq!(field.word_at(0), 0b101);
+	}
+
+	#[test]
+	fn rare_but_not_singleton_tag_does_not_densify_early() {
+		// 2 bits set in the first 7 elements would already satisfy the
+		// old, length-based threshold (2 offsets * 4 bytes = 8 >= 1
+		// dense byte for a single u8 word), permanently promoting a tag
+		// that's actually rare over its full lifetime. The fix compares
+		// against the whole chunk's dense capacity instead, so this
+		// should stay sparse.
+		let mut field = SparseBitField::<u8>::with_false(0);
+		field.push(true);
+		for _ in 0..5 {
+			field.push(false);
+		}
+		field.push(true);
+
+		assert!(!field.is_dense_enough());
+		assert!(matches!(field.chunks[0], Chunk::Sparse(_)));
+	}
+
+	#[test]
+	fn densifies_once_offsets_outgrow_dense_words() {
+		let mut field = SparseBitField::<u8>::with_false(0);
+
+		// u8's dense words take 1 byte each, so a chunk of 65536 bits
+		// is 8192 dense bytes; pushing more than 8192 / 4 = 2048 true
+		// bits should flip the chunk over to dense.
+		for _ in 0..2050 {
+			field.push(true);
+		}
+
+		assert!(matches!(field.chunks[0], Chunk::Dense(_)));
+
+		field.push(false);
+		field.push(true);
+
+		assert_eq!(field.get_unchecked(2049), true);
+		assert_eq!(field.get_unchecked(2050), false);
+		assert_eq!(field.get_unchecked(2051), true);
+	}
+
+	#[test]
+	fn set_unchecked_clears_a_bit_while_sparse() {
+		let mut field = SparseBitField::<u32>::with_false(0);
+		field.push(true);
+		field.push(true);
+
+		field.set_unchecked(0, false);
+		assert_eq!(field.get_unchecked(0), false);
+		assert_eq!(field.get_unchecked(1), true);
+		assert_eq!(field.word_at(0), 0b10);
+	}
+
+	#[test]
+	fn word_at_reads_every_word_by_value_while_sparse() {
+		let mut field = SparseBitField::<u8>::with_false(0);
+		for _ in 0..8 {
+			field.push(false);
+		}
+		field.push(true);
+
+		assert_eq!(field.word_at(0), 0);
+		assert_eq!(field.word_at(1), 0b1);
+		assert_eq!(field.word_at(2), 0);
+	}
+
+	#[test]
+	fn word_at_only_picks_up_offsets_in_its_own_word_range() {
+		// Offsets scattered across many words should each land in only
+		// the one `word_at` call whose range contains them, the way a
+		// binary-search-bounded lookup (rather than a full scan) would
+		// behave.
+		let mut field = SparseBitField::<u8>::with_false(0);
+		for i in 0..80u32 {
+			field.push(i % 8 == 0);
+		}
+
+		for word_index in 0..10 {
+			assert_eq!(field.word_at(word_index), 0b1);
+		}
+	}
+}
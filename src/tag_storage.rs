@@ -0,0 +1,78 @@
+use crate::bit_field::BitField;
+use crate::dyn_bit_field::DynamicBitField;
+use crate::sparse_bit_field::SparseBitField;
+
+/// How a single tag's membership bits are stored. A new tag starts out
+/// `Sparse`, since it's introduced by exactly one element having it;
+/// it's promoted to `Dense` once enough elements share it that a dense
+/// word array becomes the smaller representation, and stays there.
+/// This keeps the existing dense path as the default for tags that
+/// turn out to be common, while rare tags stay cheap indefinitely.
+pub(crate) enum TagStorage<F: BitField> {
+	Dense(DynamicBitField<F>),
+	Sparse(SparseBitField<F>),
+}
+
+impl<F: BitField> TagStorage<F> {
+	/// Creates a TagStorage with `n_bits` bits set to false.
+	pub(crate) fn with_false(n_bits: usize) -> TagStorage<F> {
+		TagStorage::Sparse(SparseBitField::with_false(n_bits))
+	}
+
+	/// Returns the length in bits of the TagStorage
+	pub(crate) fn len(&self) -> usize {
+		match self {
+			TagStorage::Dense(field) => field.len(),
+			TagStorage::Sparse(field) => field.len(),
+		}
+	}
+
+	/// Pushes a bit onto the TagStorage, promoting a sparse tag to
+	/// dense storage if it has grown too common to stay sparse.
+	pub(crate) fn push(&mut self, value: bool) {
+		match self {
+			TagStorage::Dense(field) => field.push(value),
+			TagStorage::Sparse(field) => {
+				field.push(value);
+
+				if field.is_dense_enough() {
+					let n_bits = F::n_bits();
+					let n_words = (field.len() + n_bits - 1) / n_bits;
+					let data = (0..n_words).map(|i| field.word_at(i)).collect();
+					*self = TagStorage::Dense(DynamicBitField::from_raw(data, field.len()));
+				}
+			},
+		}
+	}
+
+	/// Returns a value at the index.
+	/// Panics if the index is out of bounds
+	pub(crate) fn get_unchecked(&self, index: usize) -> bool {
+		match self {
+			TagStorage::Dense(field) => field.get_unchecked(index),
+			TagStorage::Sparse(field) => field.get_unchecked(index),
+		}
+	}
+
+	/// Sets the bit at `index` to `value`, overwriting whatever was
+	/// there before. Used by `TagVec::remove` to clear an element's
+	/// bit out of a tag field without disturbing any other element's
+	/// index.
+	/// Panics if the index is out of bounds
+	pub(crate) fn set_unchecked(&mut self, index: usize, value: bool) {
+		match self {
+			TagStorage::Dense(field) => field.set_unchecked(index, value),
+			TagStorage::Sparse(field) => field.set_unchecked(index, value),
+		}
+	}
+
+	/// Returns the `data_index`'th word, the same word `Query` would
+	/// read from a dense field's raw data, regardless of whether this
+	/// tag is actually stored densely or sparsely.
+	pub(crate) fn word_at(&self, data_index: usize) -> F {
+		match self {
+			TagStorage::Dense(field) => field.word_at(data_index),
+			TagStorage::Sparse(field) => field.word_at(data_index),
+		}
+	}
+}